@@ -14,10 +14,12 @@
 use std::boxed::FnBox;
 use std::fmt::Debug;
 use std::io::Write;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 use mio::Token;
-use grpc::{RpcContext, UnarySink, ClientStreamingSink, RequestStream};
+use grpc::{RpcContext, UnarySink, ClientStreamingSink, DuplexSink, RequestStream};
+use futures::sync::mpsc as futures_mpsc;
 use futures::{future, Future, Stream};
 use futures::sync::oneshot;
 use tokio_core::reactor::Remote;
@@ -28,15 +30,70 @@ use kvproto::kvrpcpb::*;
 use kvproto::coprocessor::*;
 use kvproto::errorpb::{Error as RegionError, ServerIsBusy};
 
+use prometheus::{HistogramVec, CounterVec, Collector};
 use util::worker::Scheduler;
 use util::buf::PipeBuffer;
+use util::time::duration_to_sec;
 use storage::{self, Storage, Key, Options, Mutation};
 use super::transport::RaftStoreRouter;
 use super::coprocessor::{RequestTask, EndPointTask};
 use super::snap::Task as SnapTask;
 use super::metrics::*;
+use super::server::{GcTask, GcWorkerState, JobState};
 use super::Error;
 
+lazy_static! {
+    // Per-verb handling latency, so a slow `kv_prewrite` doesn't hide
+    // behind the coarse `RECV_MSG_COUNTER` "kv" bucket.
+    static ref GRPC_MSG_HISTOGRAM_VEC: HistogramVec =
+        register_histogram_vec!("tikv_grpc_msg_duration_seconds",
+                                 "Bucketed histogram of gRPC message handling duration",
+                                 &["type"])
+            .unwrap();
+
+    // Per-verb outcome counts, split by whether the call ended in a
+    // region error, a key error, or succeeded outright.
+    static ref GRPC_MSG_COUNTER_VEC: CounterVec =
+        register_counter_vec!("tikv_grpc_msg_total",
+                               "Total number of gRPC messages by handling outcome",
+                               &["type", "outcome"])
+            .unwrap();
+}
+
+// Times a single RPC handler invocation and records the result against
+// `GRPC_MSG_HISTOGRAM_VEC`/`GRPC_MSG_COUNTER_VEC`, keyed by method name.
+// The timer is started at the top of the handler and observed once the
+// final response is ready, in the handler's last `.map`/`.then`.
+struct Timer {
+    method: &'static str,
+    start: Instant,
+}
+
+impl Timer {
+    fn start(method: &'static str) -> Timer {
+        Timer {
+            method: method,
+            start: Instant::now(),
+        }
+    }
+
+    fn observe(self, outcome: &'static str) {
+        GRPC_MSG_HISTOGRAM_VEC.with_label_values(&[self.method])
+            .observe(duration_to_sec(self.start.elapsed()));
+        GRPC_MSG_COUNTER_VEC.with_label_values(&[self.method, outcome]).inc();
+    }
+}
+
+fn classify_result<T>(res: &storage::Result<T>) -> &'static str {
+    if extract_region_error(res).is_some() {
+        "region_error"
+    } else if res.is_err() {
+        "key_error"
+    } else {
+        "ok"
+    }
+}
+
 #[derive(Clone)]
 pub struct Service<T: RaftStoreRouter + 'static> {
     core: Remote,
@@ -48,6 +105,9 @@ pub struct Service<T: RaftStoreRouter + 'static> {
     ch: T,
     // For handling snapshot.
     snap_scheduler: Scheduler<SnapTask>,
+    // For controlling the background GC scrub worker.
+    gc_scheduler: Scheduler<GcTask>,
+    gc_state: Arc<Mutex<GcWorkerState>>,
     token: Arc<AtomicUsize>, // TODO: remove it.
 }
 
@@ -56,7 +116,9 @@ impl<T: RaftStoreRouter + 'static> Service<T> {
                storage: Storage,
                end_point_scheduler: Scheduler<EndPointTask>,
                ch: T,
-               snap_scheduler: Scheduler<SnapTask>)
+               snap_scheduler: Scheduler<SnapTask>,
+               gc_scheduler: Scheduler<GcTask>,
+               gc_state: Arc<Mutex<GcWorkerState>>)
                -> Service<T> {
         Service {
             core: core,
@@ -64,6 +126,8 @@ impl<T: RaftStoreRouter + 'static> Service<T> {
             end_point_scheduler: end_point_scheduler,
             ch: ch,
             snap_scheduler: snap_scheduler,
+            gc_scheduler: gc_scheduler,
+            gc_state: gc_state,
             token: Arc::new(AtomicUsize::new(1)),
         }
     }
@@ -77,10 +141,53 @@ fn make_callback<T: Debug + Send + 'static>() -> (Box<FnBox(T) + Send>, oneshot:
     (box callback, rx)
 }
 
+// Outcome of inspecting the peer's TLS client certificate (if any) for an
+// `x509_common_name` claiming a store identity. `Unauthenticated` (no
+// `x509_common_name` at all -- mTLS disabled, or no client cert
+// requested) and `Unparseable` (a cert *was* presented but its CN isn't
+// in `SecurityConfig::peer_identity`'s "store-<id>" format) are kept
+// distinct on purpose: the former means there's nothing to check, but
+// the latter means a peer authenticated with a certificate this code
+// can't make sense of, which is exactly the kind of misrouted/
+// misconfigured connection the check exists to catch, so it must fail
+// closed rather than be silently treated the same as "no mTLS".
+enum PeerIdentity {
+    Unauthenticated,
+    Store(u64),
+    Unparseable(String),
+}
+
+// `RequestAndRequireClientCertificateAndVerify` only proves the cert
+// chains to the trusted CA; comparing the identity it claims against the
+// store id a raft/snapshot message actually claims to be from is what
+// catches a connection that reached the wrong store.
+fn peer_store_identity(ctx: &RpcContext) -> PeerIdentity {
+    let cn = ctx.auth_context()
+        .into_iter()
+        .find(|prop| prop.name() == "x509_common_name")
+        .and_then(|prop| prop.value_str().ok().map(|s| s.to_owned()));
+    match cn {
+        None => PeerIdentity::Unauthenticated,
+        Some(cn) => {
+            let store_id = if cn.starts_with("store-") {
+                cn["store-".len()..].parse().ok()
+            } else {
+                None
+            };
+            match store_id {
+                Some(store_id) => PeerIdentity::Store(store_id),
+                None => PeerIdentity::Unparseable(cn),
+            }
+        }
+    }
+}
+
 impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
     fn kv_get(&self, _: RpcContext, mut req: GetRequest, sink: UnarySink<GetResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_get");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let (cb, future) = make_callback();
@@ -90,8 +197,10 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                            cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut res = GetResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         res.set_region_error(err);
                     } else {
@@ -112,6 +221,8 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
     fn kv_scan(&self, _: RpcContext, mut req: ScanRequest, sink: UnarySink<ScanResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_scan");
+
         let storage = self.storage.clone();
         self.core
             .spawn(move |_| {
@@ -127,8 +238,10 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                                 cb)
                     .unwrap();
                 future.map_err(Error::from)
-                    .map(|v| {
+                    .map(move |v| {
                         let mut resp = ScanResponse::new();
+                        let outcome = classify_result(&v);
+                        timer.observe(outcome);
                         if let Some(err) = extract_region_error(&v) {
                             resp.set_region_error(err);
                         } else {
@@ -148,6 +261,8 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                    sink: UnarySink<PrewriteResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_prewrite");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let mutations = req.take_mutations()
@@ -173,8 +288,10 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                                 cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = PrewriteResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else {
@@ -191,6 +308,8 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
     fn kv_commit(&self, _: RpcContext, mut req: CommitRequest, sink: UnarySink<CommitResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_commit");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let keys = req.get_keys().iter().map(|x| Key::from_raw(x)).collect();
@@ -203,8 +322,10 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                               cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = CommitResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else if let Err(e) = v {
@@ -224,6 +345,8 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                   sink: UnarySink<CleanupResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_cleanup");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let (cb, future) = make_callback();
@@ -233,8 +356,10 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                                cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = CleanupResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else if let Err(e) = v {
@@ -257,6 +382,8 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                     mut req: BatchGetRequest,
                     sink: UnarySink<BatchGetResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("kv_batch_get");
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let keys = req.get_keys().into_iter().map(|x| Key::from_raw(x)).collect();
@@ -265,8 +392,10 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             storage.async_batch_get(req.take_context(), keys, req.get_version(), cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = BatchGetResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else {
@@ -286,6 +415,8 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                          sink: UnarySink<BatchRollbackResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_batch_rollback");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let keys = req.get_keys().into_iter().map(|x| Key::from_raw(x)).collect();
@@ -294,8 +425,10 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             storage.async_rollback(req.take_context(), keys, req.get_start_version(), cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = BatchRollbackResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else if let Err(e) = v {
@@ -315,14 +448,18 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                     sink: UnarySink<ScanLockResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_scan_lock");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let (cb, future) = make_callback();
             storage.async_scan_lock(req.take_context(), req.get_max_version(), cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = ScanLockResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else {
@@ -345,6 +482,8 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                        sink: UnarySink<ResolveLockResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_resolve_lock");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let commit_ts = match req.get_commit_version() {
@@ -356,8 +495,10 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
             storage.async_resolve_lock(req.take_context(), req.get_start_version(), commit_ts, cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = ResolveLockResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else if let Err(e) = v {
@@ -374,13 +515,17 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
     fn kv_gc(&self, _: RpcContext, mut req: GCRequest, sink: UnarySink<GCResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("kv_gc");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let (cb, future) = make_callback();
             storage.async_gc(req.take_context(), req.get_safe_point(), cb).unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = GCResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else if let Err(e) = v {
@@ -394,16 +539,162 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         });
     }
 
+    // gc_control lets an operator start, pause, resume, or cancel the
+    // background GC scrub worker, and adjust its tranquility (how many
+    // keys it processes per tick) without restarting the node.
+    fn gc_control(&self,
+                  _: RpcContext,
+                  req: GcControlRequest,
+                  sink: UnarySink<GcControlResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("gc_control");
+        let gc_scheduler = self.gc_scheduler.clone();
+        self.core.spawn(move |_| {
+            let task = match req.get_command() {
+                GcCommandType::Start => GcTask::Start { safe_point: req.get_safe_point() },
+                GcCommandType::Pause => GcTask::Pause,
+                GcCommandType::Resume => GcTask::Resume,
+                GcCommandType::Cancel => GcTask::Cancel,
+                GcCommandType::SetTranquility => {
+                    GcTask::SetTranquility(req.get_tranquility() as usize)
+                }
+            };
+
+            let mut resp = GcControlResponse::new();
+            let outcome = if let Err(e) = gc_scheduler.schedule(task) {
+                resp.set_error(format!("{}", e));
+                "key_error"
+            } else {
+                "ok"
+            };
+            timer.observe(outcome);
+            sink.success(resp)
+                .map_err(Error::from)
+                .map(|_| ())
+                .map_err(|e| error!("gc_control failed: {:?}", e))
+        });
+    }
+
+    // list_background_jobs reports the Active/Idle/Dead state (and last
+    // error, if any) of long-lived background jobs such as the GC scrub
+    // worker, so operators can see what is running without scraping logs.
+    fn list_background_jobs(&self,
+                             _: RpcContext,
+                             _: ListBackgroundJobsRequest,
+                             sink: UnarySink<ListBackgroundJobsResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("list_background_jobs");
+        let gc_state = self.gc_state.clone();
+        self.core.spawn(move |_| {
+            let gc_info = gc_state.lock().unwrap().info();
+            timer.observe("ok");
+
+            let mut job = BackgroundJobInfo::new();
+            job.set_name(gc_info.name);
+            job.set_state(match gc_info.state {
+                JobState::Active => BackgroundJobState::Active,
+                JobState::Idle => BackgroundJobState::Idle,
+                JobState::Dead => BackgroundJobState::Dead,
+            });
+            if let Some(err) = gc_info.last_error {
+                job.set_last_error(err);
+            }
+
+            // TODO: report snapshot send/apply jobs here too, once the
+            // snap scheduler exposes per-job state like GC does.
+            let mut resp = ListBackgroundJobsResponse::new();
+            resp.set_jobs(RepeatedField::from_vec(vec![job]));
+            sink.success(resp)
+                .map_err(Error::from)
+                .map(|_| ())
+                .map_err(|e| error!("list_background_jobs failed: {:?}", e))
+        });
+    }
+
+    // metrics_snapshot exposes the current per-verb latency histogram and
+    // outcome counters gathered by `Timer`, so operators can read tail
+    // latency per RPC without scraping Prometheus.
+    fn metrics_snapshot(&self,
+                        _: RpcContext,
+                        _: MetricsSnapshotRequest,
+                        sink: UnarySink<MetricsSnapshotResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        self.core.spawn(move |_| {
+            let mut histograms = vec![];
+            for family in GRPC_MSG_HISTOGRAM_VEC.collect() {
+                for metric in family.get_metric() {
+                    let method = metric.get_label()
+                        .iter()
+                        .find(|p| p.get_name() == "type")
+                        .map(|p| p.get_value().to_owned())
+                        .unwrap_or_default();
+                    let h = metric.get_histogram();
+
+                    let mut snap = MethodHistogram::new();
+                    snap.set_method(method);
+                    snap.set_count(h.get_sample_count());
+                    snap.set_sum(h.get_sample_sum());
+                    snap.set_buckets(RepeatedField::from_vec(h.get_bucket()
+                        .iter()
+                        .map(|b| {
+                            let mut bucket = HistogramBucket::new();
+                            bucket.set_upper_bound(b.get_upper_bound());
+                            bucket.set_cumulative_count(b.get_cumulative_count());
+                            bucket
+                        })
+                        .collect()));
+                    histograms.push(snap);
+                }
+            }
+
+            let mut counters = vec![];
+            for family in GRPC_MSG_COUNTER_VEC.collect() {
+                for metric in family.get_metric() {
+                    let mut method = String::new();
+                    let mut outcome = String::new();
+                    for p in metric.get_label() {
+                        match p.get_name() {
+                            "type" => method = p.get_value().to_owned(),
+                            "outcome" => outcome = p.get_value().to_owned(),
+                            _ => {}
+                        }
+                    }
+
+                    let mut snap = MethodCounter::new();
+                    snap.set_method(method);
+                    snap.set_outcome(outcome);
+                    snap.set_value(metric.get_counter().get_value());
+                    counters.push(snap);
+                }
+            }
+
+            let mut resp = MetricsSnapshotResponse::new();
+            resp.set_histograms(RepeatedField::from_vec(histograms));
+            resp.set_counters(RepeatedField::from_vec(counters));
+            sink.success(resp)
+                .map_err(Error::from)
+                .map(|_| ())
+                .map_err(|e| error!("metrics_snapshot failed: {:?}", e))
+        });
+    }
+
     fn raw_get(&self, _: RpcContext, mut req: RawGetRequest, sink: UnarySink<RawGetResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("raw_get");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let (cb, future) = make_callback();
             storage.async_raw_get(req.take_context(), req.take_key(), cb).unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = RawGetResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else {
@@ -424,14 +715,18 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
     fn raw_put(&self, _: RpcContext, mut req: RawPutRequest, sink: UnarySink<RawPutResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("raw_put");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let (cb, future) = make_callback();
             storage.async_raw_put(req.take_context(), req.take_key(), req.take_value(), cb)
                 .unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = RawPutResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else if let Err(e) = v {
@@ -451,13 +746,17 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                   sink: UnarySink<RawDeleteResponse>) {
         RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
 
+        let timer = Timer::start("raw_delete");
+
         let storage = self.storage.clone();
         self.core.spawn(move |_| {
             let (cb, future) = make_callback();
             storage.async_raw_delete(req.take_context(), req.take_key(), cb).unwrap();
             future.map_err(Error::from)
-                .map(|v| {
+                .map(move |v| {
                     let mut resp = RawDeleteResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
                     if let Some(err) = extract_region_error(&v) {
                         resp.set_region_error(err);
                     } else if let Err(e) = v {
@@ -471,14 +770,242 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
         });
     }
 
+    fn raw_compare_and_swap(&self,
+                            _: RpcContext,
+                            mut req: RawCASRequest,
+                            sink: UnarySink<RawCASResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("raw_compare_and_swap");
+
+        let storage = self.storage.clone();
+        self.core.spawn(move |_| {
+            let previous_value = if req.has_previous_value() {
+                Some(req.take_previous_value())
+            } else {
+                None
+            };
+
+            let (cb, future) = make_callback();
+            storage.async_raw_cas(req.take_context(),
+                               req.take_key(),
+                               previous_value,
+                               req.take_new_value(),
+                               cb)
+                .unwrap();
+            future.map_err(Error::from)
+                .map(move |v| {
+                    let mut resp = RawCASResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
+                    if let Some(err) = extract_region_error(&v) {
+                        resp.set_region_error(err);
+                    } else {
+                        match v {
+                            Ok((succeeded, previous)) => {
+                                resp.set_succeeded(succeeded);
+                                resp.set_not_equal(!succeeded);
+                                if let Some(previous) = previous {
+                                    resp.set_previous_value(previous);
+                                }
+                            }
+                            Err(e) => resp.set_error(format!("{}", e)),
+                        }
+                    }
+                    resp
+                })
+                .and_then(|res| sink.success(res).map_err(Error::from))
+                .map(|_| ())
+                .map_err(|e| error!("raw_compare_and_swap failed: {:?}", e))
+        });
+    }
+
+    fn raw_scan(&self, _: RpcContext, mut req: RawScanRequest, sink: UnarySink<RawScanResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("raw_scan");
+
+        let storage = self.storage.clone();
+        self.core.spawn(move |_| {
+            let (cb, future) = make_callback();
+            storage.async_raw_scan(req.take_context(),
+                                req.take_start_key(),
+                                req.take_end_key(),
+                                req.get_limit() as usize,
+                                req.get_key_only(),
+                                cb)
+                .unwrap();
+            future.map_err(Error::from)
+                .map(move |v| {
+                    let mut resp = RawScanResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
+                    if let Some(err) = extract_region_error(&v) {
+                        resp.set_region_error(err);
+                    } else {
+                        resp.set_kvs(RepeatedField::from_vec(extract_raw_kv_pairs(v)));
+                    }
+                    resp
+                })
+                .and_then(|res| sink.success(res).map_err(Error::from))
+                .map(|_| ())
+                .map_err(|e| error!("raw_scan failed: {:?}", e))
+        });
+    }
+
+    fn raw_delete_range(&self,
+                        _: RpcContext,
+                        mut req: RawDeleteRangeRequest,
+                        sink: UnarySink<RawDeleteRangeResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("raw_delete_range");
+
+        let storage = self.storage.clone();
+        self.core.spawn(move |_| {
+            let (cb, future) = make_callback();
+            storage.async_raw_delete_range(req.take_context(),
+                                        req.take_start_key(),
+                                        req.take_end_key(),
+                                        cb)
+                .unwrap();
+            future.map_err(Error::from)
+                .map(move |v| {
+                    let mut resp = RawDeleteRangeResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
+                    if let Some(err) = extract_region_error(&v) {
+                        resp.set_region_error(err);
+                    } else if let Err(e) = v {
+                        resp.set_error(format!("{}", e));
+                    }
+                    resp
+                })
+                .and_then(|res| sink.success(res).map_err(Error::from))
+                .map(|_| ())
+                .map_err(|e| error!("raw_delete_range failed: {:?}", e))
+        });
+    }
+
+    fn raw_batch_get(&self,
+                     _: RpcContext,
+                     mut req: RawBatchGetRequest,
+                     sink: UnarySink<RawBatchGetResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("raw_batch_get");
+
+        let storage = self.storage.clone();
+        self.core.spawn(move |_| {
+            let keys = req.take_keys().into_vec();
+
+            let (cb, future) = make_callback();
+            storage.async_raw_batch_get(req.take_context(), keys, cb).unwrap();
+            future.map_err(Error::from)
+                .map(move |v| {
+                    let mut resp = RawBatchGetResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
+                    if let Some(err) = extract_region_error(&v) {
+                        resp.set_region_error(err);
+                    } else {
+                        resp.set_pairs(RepeatedField::from_vec(extract_raw_kv_pairs(v)));
+                    }
+                    resp
+                })
+                .and_then(|res| sink.success(res).map_err(Error::from))
+                .map(|_| ())
+                .map_err(|e| error!("raw_batch_get failed: {:?}", e))
+        });
+    }
+
+    fn raw_batch_put(&self,
+                      _: RpcContext,
+                      mut req: RawBatchPutRequest,
+                      sink: UnarySink<RawBatchPutResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("raw_batch_put");
+
+        let storage = self.storage.clone();
+        self.core.spawn(move |_| {
+            let pairs = req.take_pairs()
+                .into_iter()
+                .map(|mut x| (x.take_key(), x.take_value()))
+                .collect();
+
+            let (cb, future) = make_callback();
+            storage.async_raw_batch_put(req.take_context(), pairs, cb).unwrap();
+            future.map_err(Error::from)
+                .map(move |v| {
+                    let mut resp = RawBatchPutResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
+                    if let Some(err) = extract_region_error(&v) {
+                        resp.set_region_error(err);
+                    } else if let Err(e) = v {
+                        resp.set_error(format!("{}", e));
+                    }
+                    resp
+                })
+                .and_then(|res| sink.success(res).map_err(Error::from))
+                .map(|_| ())
+                .map_err(|e| error!("raw_batch_put failed: {:?}", e))
+        });
+    }
+
+    fn raw_batch_delete(&self,
+                        _: RpcContext,
+                        mut req: RawBatchDeleteRequest,
+                        sink: UnarySink<RawBatchDeleteResponse>) {
+        RECV_MSG_COUNTER.with_label_values(&["kv"]).inc();
+
+        let timer = Timer::start("raw_batch_delete");
+
+        let storage = self.storage.clone();
+        self.core.spawn(move |_| {
+            let keys = req.take_keys().into_vec();
+
+            let (cb, future) = make_callback();
+            storage.async_raw_batch_delete(req.take_context(), keys, cb).unwrap();
+            future.map_err(Error::from)
+                .map(move |v| {
+                    let mut resp = RawBatchDeleteResponse::new();
+                    let outcome = classify_result(&v);
+                    timer.observe(outcome);
+                    if let Some(err) = extract_region_error(&v) {
+                        resp.set_region_error(err);
+                    } else if let Err(e) = v {
+                        resp.set_error(format!("{}", e));
+                    }
+                    resp
+                })
+                .and_then(|res| sink.success(res).map_err(Error::from))
+                .map(|_| ())
+                .map_err(|e| error!("raw_batch_delete failed: {:?}", e))
+        });
+    }
+
     fn coprocessor(&self, _: RpcContext, req: Request, sink: UnarySink<Response>) {
         RECV_MSG_COUNTER.with_label_values(&["coprocessor"]).inc();
 
+        let timer = Timer::start("coprocessor");
         let end_point_scheduler = self.end_point_scheduler.clone();
         self.core.spawn(move |_| {
             let (cb, future) = make_callback();
             end_point_scheduler.schedule(EndPointTask::Request(RequestTask::new(req, cb))).unwrap();
             future.map_err(Error::from)
+                .map(move |res| {
+                    let outcome = if res.has_region_error() {
+                        "region_error"
+                    } else if res.has_other_error() {
+                        "key_error"
+                    } else {
+                        "ok"
+                    };
+                    timer.observe(outcome);
+                    res
+                })
                 .and_then(|res| sink.success(res).map_err(Error::from))
                 .map(|_| ())
                 .map_err(|e| error!("coprocessor failed: {:?}", e))
@@ -486,39 +1013,110 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
     }
 
     fn raft(&self,
-            _: RpcContext,
+            ctx: RpcContext,
             stream: RequestStream<RaftMessage>,
             _: ClientStreamingSink<Done>) {
         let ch = self.ch.clone();
+        let expected_store = peer_store_identity(&ctx);
         self.core.spawn(move |_| {
             stream.map_err(Error::from)
-                .for_each(move |msg| future::result(ch.send_raft_msg(msg)).map_err(Error::from))
+                .for_each(move |msg| -> Box<Future<Item = (), Error = Error> + Send> {
+                    match expected_store {
+                        PeerIdentity::Store(expected) => {
+                            let from = msg.get_from_peer().get_store_id();
+                            if from != expected {
+                                error!("raft conn certificate is for store {} but message claims store \
+                                        {}; dropping",
+                                       expected,
+                                       from);
+                                return box future::ok(());
+                            }
+                        }
+                        PeerIdentity::Unparseable(ref cn) => {
+                            error!("raft conn presented an mTLS certificate with an unparseable \
+                                    common name {:?}; rejecting the stream",
+                                   cn);
+                            return box future::err(box_err!("unparseable peer certificate common name"));
+                        }
+                        PeerIdentity::Unauthenticated => {}
+                    }
+                    box future::result(ch.send_raft_msg(msg)).map_err(Error::from)
+                })
                 .then(|_| future::ok(()))
         });
     }
 
     fn snapshot(&self,
-                _: RpcContext,
+                ctx: RpcContext,
                 stream: RequestStream<SnapshotChunk>,
-                sink: ClientStreamingSink<Done>) {
+                sink: DuplexSink<SnapshotChunk>) {
         let token = Token(self.token.fetch_add(1, Ordering::SeqCst));
+        let expected_store = peer_store_identity(&ctx);
         let sched = self.snap_scheduler.clone();
         let sched2 = sched.clone();
+        // The sender leads with a manifest of chunk digests; we reply on
+        // this channel with the subset we don't already have, so only
+        // those chunk bodies get streamed afterwards.
+        let (out_tx, out_rx) = futures_mpsc::unbounded();
+
+        self.core.spawn(move |_| {
+            sink.send_all(out_rx.map_err(|_| Error::Sink))
+                .map(|_| ())
+                .map_err(|e| error!("send snapshot missing-set reply failed: {:?}", e))
+        });
+
         self.core.spawn(move |_| {
             stream.map_err(Error::from)
-                .for_each(move |mut chunk| {
-                    let res = if chunk.has_message() {
-                        sched.schedule(SnapTask::Register(token, chunk.take_message()))
-                            .map_err(Error::from)
+                .for_each(move |mut chunk| -> Box<Future<Item = (), Error = Error> + Send> {
+                    if chunk.has_manifest() {
+                        let digests = chunk.take_manifest().take_digests().into_vec();
+                        let (cb, future) = make_callback();
+                        let out_tx = out_tx.clone();
+                        box future::result(sched.schedule(SnapTask::Manifest(token, digests, cb))
+                                .map_err(Error::from))
+                            .and_then(move |_| future.map_err(Error::from))
+                            .and_then(move |missing: Vec<u32>| {
+                                let mut missing_msg = MissingChunks::new();
+                                missing_msg.set_indices(missing);
+                                let mut resp_chunk = SnapshotChunk::new();
+                                resp_chunk.set_missing(missing_msg);
+                                out_tx.unbounded_send(resp_chunk).map_err(|_| Error::Sink)
+                            })
+                    } else if chunk.has_message() {
+                        let msg = chunk.take_message();
+                        match expected_store {
+                            PeerIdentity::Store(expected) => {
+                                let from = msg.get_from_peer().get_store_id();
+                                if from != expected {
+                                    error!("snapshot {:?} certificate is for store {} but message \
+                                            claims store {}; dropping",
+                                           token,
+                                           expected,
+                                           from);
+                                    return box future::err(box_err!("snapshot peer identity mismatch"));
+                                }
+                            }
+                            PeerIdentity::Unparseable(ref cn) => {
+                                error!("snapshot {:?} presented an mTLS certificate with an \
+                                        unparseable common name {:?}; rejecting the stream",
+                                       token,
+                                       cn);
+                                return box future::err(box_err!("unparseable peer certificate common \
+                                                                  name"));
+                            }
+                            PeerIdentity::Unauthenticated => {}
+                        }
+                        box future::result(sched.schedule(SnapTask::Register(token, msg))
+                            .map_err(Error::from))
                     } else if !chunk.get_data().is_empty() {
                         // TODO: Remove PipeBuffer or take good use of it.
                         let mut b = PipeBuffer::new(chunk.get_data().len());
                         b.write_all(chunk.get_data()).unwrap();
-                        sched.schedule(SnapTask::Write(token, b)).map_err(Error::from)
+                        box future::result(sched.schedule(SnapTask::Write(token, b))
+                            .map_err(Error::from))
                     } else {
-                        Err(box_err!("empty chunk"))
-                    };
-                    future::result(res)
+                        box future::err(box_err!("empty chunk"))
+                    }
                 })
                 .then(move |res| {
                     let res = match res {
@@ -530,7 +1128,6 @@ impl<T: RaftStoreRouter + 'static> tikvpb_grpc::Tikv for Service<T> {
                     };
                     future::result(res.map_err(Error::from))
                 })
-                .and_then(|_| sink.success(Done::new()).map_err(Error::from))
                 .then(|_| future::ok(()))
         });
     }
@@ -619,6 +1216,22 @@ fn extract_kv_pairs(res: storage::Result<Vec<storage::Result<storage::KvPair>>>)
     pairs
 }
 
+fn extract_raw_kv_pairs(res: storage::Result<Vec<storage::KvPair>>) -> Vec<KvPair> {
+    match res {
+        Ok(res) => {
+            res.into_iter()
+                .map(|(key, value)| {
+                    let mut pair = KvPair::new();
+                    pair.set_key(key);
+                    pair.set_value(value);
+                    pair
+                })
+                .collect()
+        }
+        Err(_) => vec![],
+    }
+}
+
 fn extract_key_errors(res: storage::Result<Vec<storage::Result<()>>>) -> Vec<KeyError> {
     let mut errs = vec![];
     match res {
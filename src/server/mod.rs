@@ -0,0 +1,193 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::result;
+
+use futures::sync::oneshot::Canceled;
+use grpc;
+use kvproto::raft_serverpb::RaftMessage;
+
+use raftstore;
+use storage;
+
+pub mod server;
+pub mod grpc_service;
+pub mod snap;
+pub mod transport;
+pub mod resolve;
+pub mod coprocessor;
+pub mod metrics;
+
+pub use self::server::{Server, ServerChannel, SecurityConfig, RaftTransportKind, create_event_loop};
+pub use self::transport::RaftStoreRouter;
+pub use self::resolve::StoreAddrResolver;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Grpc(grpc::Error),
+    // A futures `Sink` (e.g. the grpc snapshot/raft message duplex
+    // stream) was closed by the other end before we finished writing it.
+    Sink,
+    Canceled(Canceled),
+    RaftServer(raftstore::Error),
+    // A `Conn::send`'s outbound priority lane was full (backpressure, not
+    // a dead connection). Kept distinct from `Other` so callers can drop
+    // just the one message instead of tearing down/backing off the whole
+    // connection; see `server::PriorityLanes::send`.
+    LaneFull,
+    Other(Box<StdError + Sync + Send>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "Io {}", e),
+            Error::Grpc(ref e) => write!(f, "Grpc {}", e),
+            Error::Sink => write!(f, "sink closed"),
+            Error::Canceled(ref e) => write!(f, "Canceled {}", e),
+            Error::RaftServer(ref e) => write!(f, "RaftServer {}", e),
+            Error::LaneFull => write!(f, "outbound lane is full"),
+            Error::Other(ref e) => write!(f, "Other {}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) => e.description(),
+            Error::Grpc(ref e) => e.description(),
+            Error::Sink => "sink closed",
+            Error::Canceled(ref e) => e.description(),
+            Error::RaftServer(ref e) => e.description(),
+            Error::LaneFull => "outbound lane is full",
+            Error::Other(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<grpc::Error> for Error {
+    fn from(e: grpc::Error) -> Error {
+        Error::Grpc(e)
+    }
+}
+
+impl From<Canceled> for Error {
+    fn from(e: Canceled) -> Error {
+        Error::Canceled(e)
+    }
+}
+
+impl From<raftstore::Error> for Error {
+    fn from(e: raftstore::Error) -> Error {
+        Error::RaftServer(e)
+    }
+}
+
+impl From<Box<StdError + Sync + Send>> for Error {
+    fn from(e: Box<StdError + Sync + Send>) -> Error {
+        Error::Other(e)
+    }
+}
+
+// A raft message bundled with the id the sender used to tag it, so a
+// reply or a delivery failure can be matched back to the right send
+// attempt.
+#[derive(Debug)]
+pub struct ConnData {
+    pub msg_id: u64,
+    pub msg: RaftMessage,
+}
+
+impl ConnData {
+    pub fn new(msg_id: u64, msg: RaftMessage) -> ConnData {
+        ConnData {
+            msg_id: msg_id,
+            msg: msg,
+        }
+    }
+
+    pub fn is_snapshot(&self) -> bool {
+        self.msg.get_message().has_snapshot()
+    }
+}
+
+// Messages routed through `Server`'s own mio event loop.
+pub enum Msg {
+    Quit,
+    SendStore { store_id: u64, data: ConnData },
+    ResolveResult {
+        store_id: u64,
+        sock_addr: Result<SocketAddr>,
+        data: ConnData,
+    },
+    CloseConn { conn_id: u64 },
+}
+
+const DEFAULT_LISTENING_ADDR: &'static str = "127.0.0.1:20160";
+const DEFAULT_NOTIFY_CAPACITY: usize = 40960;
+const DEFAULT_MESSAGES_PER_TICK: usize = 4096;
+const DEFAULT_END_POINT_CONCURRENCY: usize = 4;
+
+// Server configuration, loaded from the node's TOML config file.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub addr: String,
+    pub notify_capacity: usize,
+    pub messages_per_tick: usize,
+    pub end_point_concurrency: usize,
+    pub storage: storage::Config,
+    pub raft_store: raftstore::store::Config,
+    // TLS (CA/cert/key) for the inbound grpc server and every outbound
+    // raft `Conn`; see `server::SecurityConfig`. Empty/disabled unless
+    // all three paths are set in the config file.
+    pub security: SecurityConfig,
+    // Which `RaftTransport` implementation `Server::run` uses for raft
+    // messages; see `server::RaftTransportKind`.
+    pub raft_transport: RaftTransportKind,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            addr: DEFAULT_LISTENING_ADDR.to_owned(),
+            notify_capacity: DEFAULT_NOTIFY_CAPACITY,
+            messages_per_tick: DEFAULT_MESSAGES_PER_TICK,
+            end_point_concurrency: DEFAULT_END_POINT_CONCURRENCY,
+            storage: storage::Config::default(),
+            raft_store: raftstore::store::Config::default(),
+            security: SecurityConfig::default(),
+            raft_transport: RaftTransportKind::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config::default()
+    }
+}
@@ -11,22 +11,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::fmt;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
 use std::boxed::Box;
 use std::net::{SocketAddr, IpAddr};
 use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::fs::File;
+use std::io::Read;
 use futures::sync::mpsc;
 use futures::{Stream, Future, Sink};
 use tokio_core::reactor::{Handle as CoreHandle, Remote as RemoteCore};
 use mio::{Handler, EventLoop, EventLoopConfig};
-use grpc::{Server as GrpcServer, ServerBuilder, Environment, ChannelBuilder};
+use grpc::{Server as GrpcServer, ServerBuilder, Environment, ChannelBuilder, ServerCredentials,
+           ServerCredentialsBuilder, ChannelCredentials, ChannelCredentialsBuilder,
+           CertificateRequestType};
+use protobuf::Message;
+use quinn;
+use rand::{self, Rng};
+use prometheus::GaugeVec;
 use kvproto::tikvpb_grpc::*;
 use kvproto::raft_serverpb::*;
-use util::worker::{Stopped, Worker};
+use kvproto::eraftpb::MessageType;
+use util::worker::{Stopped, Worker, Scheduler};
 use util::worker::{FutureWorker, FutureRunnable};
 use util::transport::SendCh;
+use util::time::duration_to_sec;
 use storage::Storage;
 use raftstore::store::{SnapshotStatusMsg, SnapManager};
 use raft::SnapshotStatus;
@@ -66,6 +79,11 @@ pub struct Server<T: RaftStoreRouter + 'static, S: StoreAddrResolver> {
     // Grpc server.
     env: Arc<Environment>,
     grpc_server: GrpcServer,
+    // TLS material for both the inbound grpc server and the outbound raft
+    // connections `SendRunner` opens; empty/disabled unless configured.
+    security: SecurityConfig,
+    // Which `RaftTransport` impl `run` builds for `SendRunner`.
+    raft_transport: RaftTransportKind,
     local_addr: SocketAddr,
     // Addrs map for communicating with other raft stores.
     store_addrs: HashMap<u64, SocketAddr>,
@@ -83,6 +101,10 @@ pub struct Server<T: RaftStoreRouter + 'static, S: StoreAddrResolver> {
     snap_worker: Worker<SnapTask>,
     // For sending raft messages to other stores.
     raft_msg_worker: FutureWorker<SendTask>,
+    // Long-lived GC scrub worker; can be paused/resumed/cancelled and
+    // rate-limited by an operator instead of firing-and-forgetting.
+    gc_worker: FutureWorker<GcTask>,
+    gc_state: Arc<Mutex<GcWorkerState>>,
 }
 
 impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
@@ -99,19 +121,28 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
         let end_point_worker = Worker::new("end-point-worker");
         let snap_worker = Worker::new("snap-handler");
         let raft_msg_worker = FutureWorker::new("raft-msg-worker");
+        let gc_worker = FutureWorker::new("gc-worker");
+        let gc_state = Arc::new(Mutex::new(GcWorkerState::new()));
 
         let h = Service::new(core,
                              storage.clone(),
                              end_point_worker.scheduler(),
                              ch.raft_router.clone(),
-                             snap_worker.scheduler());
+                             snap_worker.scheduler(),
+                             gc_worker.scheduler(),
+                             gc_state.clone());
         let env = Arc::new(Environment::new(1));
         let addr = try!(SocketAddr::from_str(&cfg.addr));
         let ip = format!("{}", addr.ip());
-        let mut grpc_server = ServerBuilder::new(env.clone())
-            .register_service(create_tikv(h))
-            .bind(ip, addr.port() as u32)
-            .build();
+        let security = cfg.security.clone();
+        let mut server_builder = ServerBuilder::new(env.clone()).register_service(create_tikv(h));
+        server_builder = if security.is_enabled() {
+            let creds = try!(build_server_credentials(&security));
+            server_builder.bind_secure(ip, addr.port() as u32, creds)
+        } else {
+            server_builder.bind(ip, addr.port() as u32)
+        };
+        let mut grpc_server = server_builder.build();
         grpc_server.start();
 
         let addr = {
@@ -123,6 +154,8 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
             sendch: sendch,
             env: env,
             grpc_server: grpc_server,
+            security: security,
+            raft_transport: cfg.raft_transport,
             local_addr: addr,
             store_addrs: HashMap::default(),
             store_resolving: HashSet::default(),
@@ -134,6 +167,8 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
             snap_mgr: snap_mgr,
             snap_worker: snap_worker,
             raft_msg_worker: raft_msg_worker,
+            gc_worker: gc_worker,
+            gc_state: gc_state,
         };
 
         Ok(svr)
@@ -143,7 +178,23 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
         let ch = self.get_sendch();
         let snap_runner = SnapHandler::new(self.snap_mgr.clone(), self.ch.raft_router.clone(), ch);
         box_try!(self.snap_worker.start(snap_runner));
-        box_try!(self.raft_msg_worker.start(SendRunner::new(self.env.clone())));
+        let transport: Box<RaftTransport> = match self.raft_transport {
+            RaftTransportKind::Grpc => {
+                Box::new(GrpcTransport::new(self.env.clone(), self.security.clone()))
+            }
+            RaftTransportKind::Quic => {
+                let quic_cfg = try!(build_quic_client_config(&self.security));
+                Box::new(QuicTransport::new(quic_cfg))
+            }
+        };
+        let raft_msg_runner = SendRunner::with_transport(transport,
+                                                         self.raft_msg_worker.scheduler(),
+                                                         self.ch.raft_router.clone());
+        box_try!(self.raft_msg_worker.start(raft_msg_runner));
+        let gc_runner = GcRunner::new(self.storage.clone(),
+                                      self.gc_worker.scheduler(),
+                                      self.gc_state.clone());
+        box_try!(self.gc_worker.start(gc_runner));
         let end_point = EndPointHost::new(self.storage.get_engine(),
                                           self.end_point_worker.scheduler(),
                                           self.end_point_concurrency);
@@ -166,10 +217,13 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
         Ok(self.local_addr)
     }
 
-    fn write_data(&mut self, addr: SocketAddr, data: ConnData) {
-        if let Err(e) = self.raft_msg_worker.schedule(SendTask {
+    fn write_data(&mut self, store_id: u64, addr: SocketAddr, data: ConnData) {
+        let priority = priority_of(&data.msg);
+        if let Err(e) = self.raft_msg_worker.schedule(SendTask::Send {
+            store_id: store_id,
             addr: addr,
             msg: data.msg,
+            priority: priority,
         }) {
             error!("send raft msg err {:?}", e);
         }
@@ -212,7 +266,7 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
 
         // check the corresponding token for store.
         if let Some(addr) = self.store_addrs.get(&store_id).cloned() {
-            return self.write_data(addr, data);
+            return self.write_data(store_id, addr, data);
         }
 
         // No connection, try to resolve it.
@@ -249,11 +303,21 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Server<T, S> {
         info!("resolve store {} address ok, addr {}", store_id, sock_addr);
         self.store_addrs.insert(store_id, sock_addr);
 
+        // Every store we learn the address of becomes a full-mesh peering
+        // target: keep a Conn warm and probe it with keepalives instead of
+        // only connecting reactively once a real raft message needs it.
+        if let Err(e) = self.raft_msg_worker.schedule(SendTask::SyncPeer {
+            store_id: store_id,
+            addr: sock_addr,
+        }) {
+            error!("failed to register store {} for peering: {:?}", store_id, e);
+        }
+
         if data.is_snapshot() {
             return self.send_snapshot_sock(sock_addr, data);
         }
 
-        self.write_data(sock_addr, data)
+        self.write_data(store_id, sock_addr, data)
     }
 
     fn new_snapshot_reporter(&self, data: &ConnData) -> SnapshotReporter {
@@ -319,6 +383,7 @@ impl<T: RaftStoreRouter, S: StoreAddrResolver> Handler for Server<T, S> {
         if !el.is_running() {
             self.snap_worker.stop();
             self.raft_msg_worker.stop();
+            self.gc_worker.stop();
             self.grpc_server.shutdown();
         }
     }
@@ -357,79 +422,1042 @@ impl SnapshotReporter {
     }
 }
 
-// SendTask delivers a raft message to other store.
-pub struct SendTask {
-    pub addr: SocketAddr,
-    pub msg: RaftMessage,
+// SendTask is the unit of work processed by `SendRunner`: delivering a
+// raft message, registering a store as a proactive peering target, or
+// driving the periodic keepalive sweep across all known peers.
+pub enum SendTask {
+    Send {
+        store_id: u64,
+        addr: SocketAddr,
+        msg: RaftMessage,
+        priority: MsgPriority,
+    },
+    SyncPeer { store_id: u64, addr: SocketAddr },
+    KeepaliveTick,
 }
 
 impl fmt::Display for SendTask {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "send raft message to {:?}", self.addr)
+        match *self {
+            SendTask::Send { addr, .. } => write!(f, "send raft message to {:?}", addr),
+            SendTask::SyncPeer { store_id, addr } => {
+                write!(f, "register peer store {} at {:?}", store_id, addr)
+            }
+            SendTask::KeepaliveTick => write!(f, "raft peer keepalive tick"),
+        }
     }
 }
 
-struct Conn {
-    _client: TikvClient,
-    stream: mpsc::UnboundedSender<RaftMessage>,
+// MsgPriority classifies a `RaftMessage` for the purpose of ordering it
+// relative to other traffic to the same peer: control traffic (votes,
+// heartbeats, transfer-leader) must never queue behind a burst of normal
+// append entries, and bulk payloads (snapshots) must never starve either
+// of the other two.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MsgPriority {
+    Control,
+    Normal,
+    Bulk,
+}
+
+fn priority_of(msg: &RaftMessage) -> MsgPriority {
+    match msg.get_message().get_msg_type() {
+        MessageType::MsgSnapshot => MsgPriority::Bulk,
+        MessageType::MsgHeartbeat |
+        MessageType::MsgHeartbeatResponse |
+        MessageType::MsgRequestVote |
+        MessageType::MsgRequestVoteResponse |
+        MessageType::MsgTransferLeader => MsgPriority::Control,
+        _ => MsgPriority::Normal,
+    }
+}
+
+// TLS material for the raft transport. Empty paths mean TLS is disabled,
+// matching plaintext `ServerBuilder::bind`/`ChannelBuilder::connect`; once
+// all three are set, both the inbound grpc server and every outbound
+// `Conn` require and verify the peer's certificate against `ca_path`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityConfig {
+    pub ca_path: String,
+    pub cert_path: String,
+    pub key_path: String,
 }
 
-impl Conn {
-    fn new(env: Arc<Environment>, addr: SocketAddr, handle: &CoreHandle) -> Result<Conn> {
-        let channel = ChannelBuilder::new(env).connect(&format!("{}", addr));
+impl SecurityConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.ca_path.is_empty() && !self.cert_path.is_empty() && !self.key_path.is_empty()
+    }
+
+    // The identity a peer store's certificate is expected to present,
+    // checked at handshake time so a connection that lands on the wrong
+    // store (misrouted address, stale resolver entry) is dropped instead
+    // of silently accepted.
+    fn peer_identity(store_id: u64) -> String {
+        format!("store-{}", store_id)
+    }
+}
+
+fn read_security_file(path: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut f = box_try!(File::open(path));
+    box_try!(f.read_to_end(&mut buf));
+    Ok(buf)
+}
+
+fn build_server_credentials(security: &SecurityConfig) -> Result<ServerCredentials> {
+    let ca = try!(read_security_file(&security.ca_path));
+    let cert = try!(read_security_file(&security.cert_path));
+    let key = try!(read_security_file(&security.key_path));
+    Ok(ServerCredentialsBuilder::new()
+        .add_cert(cert, key)
+        .root_cert(ca, CertificateRequestType::RequestAndRequireClientCertificateAndVerify)
+        .build())
+}
+
+fn build_channel_credentials(security: &SecurityConfig) -> Result<ChannelCredentials> {
+    let ca = try!(read_security_file(&security.ca_path));
+    let cert = try!(read_security_file(&security.cert_path));
+    let key = try!(read_security_file(&security.key_path));
+    Ok(ChannelCredentialsBuilder::new().root_cert(ca).cert(cert, key).build())
+}
+
+// Which `RaftTransport` implementation `Server::run` wires up for
+// `SendRunner`; selected by `Config::raft_transport`. `Grpc` is the
+// default until QUIC has seen more production soak time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RaftTransportKind {
+    Grpc,
+    Quic,
+}
+
+impl Default for RaftTransportKind {
+    fn default() -> RaftTransportKind {
+        RaftTransportKind::Grpc
+    }
+}
+
+// RaftTransport abstracts how `SendRunner` opens a connection to a peer
+// store. The default is gRPC-over-TCP (`GrpcTransport`), but it can be
+// swapped for `QuicTransport` without touching `ensure_conn`/`send_message`
+// or the `conns: HashMap<SocketAddr, ConnEntry>` table.
+pub trait RaftTransport: Send {
+    fn connect(&self, store_id: u64, addr: SocketAddr, handle: &CoreHandle) -> Result<Box<Conn>>;
+}
+
+// A single open connection to a peer store, regardless of which
+// transport opened it.
+pub trait Conn: Send {
+    fn send(&self, msg: RaftMessage, priority: MsgPriority) -> Result<()>;
+}
+
+// Bounded capacity per priority lane. Normal traffic gets the largest
+// lane; bulk (snapshot) traffic gets the smallest since its messages are
+// expected to be few but heavy, and control traffic sits in between.
+const CONTROL_LANE_CAPACITY: usize = 1024;
+const NORMAL_LANE_CAPACITY: usize = 4096;
+const BULK_LANE_CAPACITY: usize = 256;
+
+// Relative number of messages drained from each lane per round of the
+// weighted round-robin, favoring control traffic without fully starving
+// the others.
+const CONTROL_LANE_WEIGHT: usize = 4;
+const NORMAL_LANE_WEIGHT: usize = 2;
+const BULK_LANE_WEIGHT: usize = 1;
+
+// Three bounded channels feeding one outbound stream. `send` uses
+// `try_send` so a full lane surfaces as an error immediately instead of
+// growing memory without bound; the caller turns that into a
+// `report_unreachable` rather than retrying forever.
+struct PriorityLanes {
+    control: mpsc::Sender<RaftMessage>,
+    normal: mpsc::Sender<RaftMessage>,
+    bulk: mpsc::Sender<RaftMessage>,
+}
+
+impl PriorityLanes {
+    fn send(&self, msg: RaftMessage, priority: MsgPriority) -> Result<()> {
+        let lane = match priority {
+            MsgPriority::Control => &self.control,
+            MsgPriority::Normal => &self.normal,
+            MsgPriority::Bulk => &self.bulk,
+        };
+        lane.clone().try_send(msg).map_err(|_| Error::LaneFull)
+    }
+}
+
+// Drains the three lanes into one stream with a weighted round-robin:
+// `CONTROL_LANE_WEIGHT` messages from `control`, then `NORMAL_LANE_WEIGHT`
+// from `normal`, then `BULK_LANE_WEIGHT` from `bulk`, repeat. An empty or
+// not-ready lane is skipped immediately rather than blocking the others.
+struct PriorityDrain {
+    lanes: [(mpsc::Receiver<RaftMessage>, usize); 3],
+    cursor: usize,
+    remaining: usize,
+}
+
+impl PriorityDrain {
+    fn new(control: mpsc::Receiver<RaftMessage>,
+           normal: mpsc::Receiver<RaftMessage>,
+           bulk: mpsc::Receiver<RaftMessage>)
+           -> PriorityDrain {
+        PriorityDrain {
+            lanes: [(control, CONTROL_LANE_WEIGHT), (normal, NORMAL_LANE_WEIGHT), (bulk, BULK_LANE_WEIGHT)],
+            cursor: 0,
+            remaining: CONTROL_LANE_WEIGHT,
+        }
+    }
+}
+
+impl Stream for PriorityDrain {
+    type Item = RaftMessage;
+    type Error = ();
+
+    fn poll(&mut self) -> ::futures::Poll<Option<RaftMessage>, ()> {
+        use futures::Async;
+
+        for _ in 0..self.lanes.len() {
+            if self.remaining == 0 {
+                self.cursor = (self.cursor + 1) % self.lanes.len();
+                self.remaining = self.lanes[self.cursor].1;
+            }
+            match self.lanes[self.cursor].0.poll() {
+                Ok(Async::Ready(Some(msg))) => {
+                    self.remaining -= 1;
+                    return Ok(Async::Ready(Some(msg)));
+                }
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => {
+                    self.cursor = (self.cursor + 1) % self.lanes.len();
+                    self.remaining = self.lanes[self.cursor].1;
+                }
+                Err(_) => {}
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+// The default transport today: one gRPC channel per peer, with outbound
+// `RaftMessage`s split across the three priority lanes described above.
+pub struct GrpcTransport {
+    env: Arc<Environment>,
+    security: SecurityConfig,
+}
+
+impl GrpcTransport {
+    pub fn new(env: Arc<Environment>, security: SecurityConfig) -> GrpcTransport {
+        GrpcTransport {
+            env: env,
+            security: security,
+        }
+    }
+}
+
+impl RaftTransport for GrpcTransport {
+    fn connect(&self, store_id: u64, addr: SocketAddr, handle: &CoreHandle) -> Result<Box<Conn>> {
+        let builder = ChannelBuilder::new(self.env.clone());
+        let channel = if self.security.is_enabled() {
+            let creds = try!(build_channel_credentials(&self.security));
+            builder.override_ssl_target(SecurityConfig::peer_identity(store_id))
+                .secure_connect(&format!("{}", addr), creds)
+        } else {
+            builder.connect(&format!("{}", addr))
+        };
         let client = TikvClient::new(channel);
-        let (tx, rx) = mpsc::unbounded();
+        let (control_tx, control_rx) = mpsc::channel(CONTROL_LANE_CAPACITY);
+        let (normal_tx, normal_rx) = mpsc::channel(NORMAL_LANE_CAPACITY);
+        let (bulk_tx, bulk_rx) = mpsc::channel(BULK_LANE_CAPACITY);
+        let drain = PriorityDrain::new(control_rx, normal_rx, bulk_rx);
         handle.spawn(client.raft().sink_map_err(Error::from)
-            .send_all(rx.map_err(|_| Error::Sink))
+            .send_all(drain.map_err(|_| Error::Sink))
             .map(|_| ())
             .map_err(|e| error!("send raftmessage failed: {:?}", e)));
-        Ok(Conn {
+        Ok(Box::new(GrpcConn {
             _client: client,
-            stream: tx,
+            lanes: PriorityLanes {
+                control: control_tx,
+                normal: normal_tx,
+                bulk: bulk_tx,
+            },
+        }))
+    }
+}
+
+struct GrpcConn {
+    _client: TikvClient,
+    lanes: PriorityLanes,
+}
+
+impl Conn for GrpcConn {
+    fn send(&self, msg: RaftMessage, priority: MsgPriority) -> Result<()> {
+        self.lanes.send(msg, priority)
+    }
+}
+
+// QUIC transport: one QUIC connection per peer, with each destination
+// region mapped onto its own uni-directional stream (picked from a small
+// fixed pool) so a stalled/lost stream for one region cannot head-of-line
+// block heartbeats for the others sharing the peer. `quinn`'s rustls
+// integration handles the TLS handshake and lets a reconnect resume the
+// previous session over 0-RTT instead of paying a full round trip.
+pub struct QuicTransport {
+    client_config: quinn::ClientConfig,
+}
+
+impl QuicTransport {
+    pub fn new(client_config: quinn::ClientConfig) -> QuicTransport {
+        QuicTransport { client_config: client_config }
+    }
+}
+
+// Builds the `quinn::ClientConfig` `Server::run` passes to `QuicTransport`
+// when `Config::raft_transport` selects QUIC, reusing the same `security`
+// CA/cert/key material as the gRPC transport. Unlike `GrpcTransport`, the
+// QUIC connect does not check the peer's certificate against the expected
+// store identity (`SecurityConfig::peer_identity`).
+fn build_quic_client_config(security: &SecurityConfig) -> Result<quinn::ClientConfig> {
+    let mut builder = quinn::ClientConfigBuilder::new();
+    if security.is_enabled() {
+        let ca = try!(read_security_file(&security.ca_path));
+        let cert = try!(read_security_file(&security.cert_path));
+        let key = try!(read_security_file(&security.key_path));
+        box_try!(builder.add_certificate_authority(&ca));
+        box_try!(builder.set_certificate(cert, key));
+    }
+    Ok(builder.build())
+}
+
+impl RaftTransport for QuicTransport {
+    fn connect(&self, _store_id: u64, addr: SocketAddr, handle: &CoreHandle) -> Result<Box<Conn>> {
+        let conn = box_try!(QuicConn::connect(self.client_config.clone(), addr, handle));
+        Ok(Box::new(conn))
+    }
+}
+
+// One stream-group index per region plus one reserved group exclusively
+// for bulk (snapshot) traffic, so a large snapshot transfer cannot share
+// -- and therefore cannot stall -- the groups normal/control traffic uses.
+const QUIC_STREAM_GROUPS: u64 = 8;
+const QUIC_BULK_STREAM_GROUP: u64 = QUIC_STREAM_GROUPS;
+
+struct QuicConn {
+    connection: quinn::Connection,
+    // A small fixed pool of streams keyed by `region_id % QUIC_STREAM_GROUPS`,
+    // rather than one stream per region, so a quiet cluster doesn't pin an
+    // unbounded number of open streams on the peer. `QUIC_BULK_STREAM_GROUP`
+    // is a dedicated extra slot for bulk traffic only.
+    streams: Mutex<HashMap<u64, quinn::SendStream>>,
+}
+
+impl QuicConn {
+    fn connect(config: quinn::ClientConfig, addr: SocketAddr, handle: &CoreHandle) -> Result<QuicConn> {
+        let connecting = box_try!(quinn::Endpoint::client(config, addr, handle));
+        let connection = box_try!(connecting.into_0rtt());
+        Ok(QuicConn {
+            connection: connection,
+            streams: Mutex::new(HashMap::default()),
         })
     }
+
+    fn stream_for(&self, group: u64) -> Result<quinn::SendStream> {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(s) = streams.get(&group) {
+            return Ok(s.clone());
+        }
+        let s = box_try!(self.connection.open_uni());
+        streams.insert(group, s.clone());
+        Ok(s)
+    }
 }
 
-// SendRunner is used for sending raft messages to other stores.
-pub struct SendRunner {
-    env: Arc<Environment>,
-    conns: HashMap<SocketAddr, Conn>,
+impl Conn for QuicConn {
+    fn send(&self, msg: RaftMessage, priority: MsgPriority) -> Result<()> {
+        let group = if priority == MsgPriority::Bulk {
+            QUIC_BULK_STREAM_GROUP
+        } else {
+            msg.get_region_id() % QUIC_STREAM_GROUPS
+        };
+        let stream = try!(self.stream_for(group));
+        let bytes = box_try!(msg.write_to_bytes());
+        box_try!(stream.write(&bytes));
+        Ok(())
+    }
+}
+
+// The state of a single peer connection slot in `SendRunner`'s connection
+// table. A failed connect or send moves the slot into `Backoff` for an
+// exponentially increasing, jittered delay instead of being retried on
+// every subsequent message; once a peer has failed enough times in a row
+// it is marked `Failed` (still retried on the same backoff schedule, but
+// distinguishable for monitoring from a peer that merely hit one blip).
+#[derive(Clone, Copy, Debug)]
+enum ConnState {
+    Connecting,
+    Connected,
+    Backoff(Instant),
+    Failed(Instant),
+}
+
+const MIN_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_MS: u64 = 10_000;
+const FAILED_THRESHOLD: u32 = 8;
+
+struct ConnEntry {
+    conn: Option<Box<Conn>>,
+    state: ConnState,
+    backoff_ms: u64,
+    consecutive_failures: u32,
+    last_send_time: Option<Instant>,
+    last_error: Option<String>,
 }
 
-impl SendRunner {
-    pub fn new(env: Arc<Environment>) -> SendRunner {
+impl ConnEntry {
+    fn new() -> ConnEntry {
+        ConnEntry {
+            conn: None,
+            state: ConnState::Connecting,
+            backoff_ms: MIN_BACKOFF_MS,
+            consecutive_failures: 0,
+            last_send_time: None,
+            last_error: None,
+        }
+    }
+
+    fn retry_at(&self) -> Option<Instant> {
+        match self.state {
+            ConnState::Backoff(until) => Some(until),
+            ConnState::Failed(until) => Some(until),
+            ConnState::Connecting | ConnState::Connected => None,
+        }
+    }
+
+    // Records a connect/send failure: doubles `backoff_ms` (capped at
+    // `MAX_BACKOFF_MS`), adds up to 25% jitter, and escalates to
+    // `ConnState::Failed` once `FAILED_THRESHOLD` consecutive failures have
+    // piled up. Split out of `SendRunner::mark_backoff` so the backoff math
+    // itself can be unit tested without standing up a whole `SendRunner`.
+    fn record_failure(&mut self, err: String, now: Instant) {
+        self.conn = None;
+        self.last_error = Some(err);
+        self.consecutive_failures += 1;
+        self.backoff_ms = cmp::min(self.backoff_ms.saturating_mul(2), MAX_BACKOFF_MS);
+        let jitter_ms = rand::thread_rng().gen_range(0, self.backoff_ms / 4 + 1);
+        let until = now + Duration::from_millis(self.backoff_ms + jitter_ms);
+        self.state = if self.consecutive_failures >= FAILED_THRESHOLD {
+            ConnState::Failed(until)
+        } else {
+            ConnState::Backoff(until)
+        };
+    }
+}
+
+// Proactive peering: once a store's address is known, `SendRunner` keeps
+// a `Conn` warm to it and probes it with keepalives, rather than only
+// discovering it is dead reactively via a failed raft send. `regions`
+// remembers which (region, to_peer) pairs we have actually routed there,
+// so a keepalive failure can eagerly report every peer we know about
+// instead of waiting for each region's own raft message to time out.
+struct PeerState {
+    addr: SocketAddr,
+    up: bool,
+    last_seen: Option<Instant>,
+    consecutive_failures: u32,
+    regions: HashMap<u64, u64>,
+}
+
+impl PeerState {
+    fn new(addr: SocketAddr) -> PeerState {
+        PeerState {
+            addr: addr,
+            up: true,
+            last_seen: None,
+            consecutive_failures: 0,
+            regions: HashMap::default(),
+        }
+    }
+}
+
+const KEEPALIVE_INTERVAL_MS: u64 = 3_000;
+const KEEPALIVE_FAILURE_THRESHOLD: u32 = 3;
+
+fn keepalive_message() -> RaftMessage {
+    // Region 0 is not a real region, so raftstore drops this message
+    // after accounting for it, making it a cheap application-level ping.
+    // A keepalive counts as successful once it is queued on the peer's
+    // `Conn`; no reply is awaited, so it does not prove the peer received it.
+    RaftMessage::new()
+}
+
+lazy_static! {
+    static ref PEER_UP_GAUGE_VEC: GaugeVec = register_gauge_vec!(
+        "tikv_server_raft_peer_up",
+        "Whether the proactive peering keepalive considers a store reachable (1) or down (0).",
+        &["store_id"]
+    ).unwrap();
+    static ref PEER_LAST_SEEN_GAUGE_VEC: GaugeVec = register_gauge_vec!(
+        "tikv_server_raft_peer_last_seen_seconds_ago",
+        "Seconds since the last successful keepalive to a peer store.",
+        &["store_id"]
+    ).unwrap();
+}
+
+// SendRunner is used for sending raft messages to other stores. Each peer
+// gets one entry in `conns`, carrying an explicit `ConnState` so a dead
+// peer is backed off instead of being blindly reconnected on every
+// message.
+pub struct SendRunner<T: RaftStoreRouter> {
+    transport: Box<RaftTransport>,
+    conns: HashMap<SocketAddr, ConnEntry>,
+    peers: HashMap<u64, PeerState>,
+    scheduler: Scheduler<SendTask>,
+    raft_router: T,
+    // Whether the self-perpetuating keepalive sweep loop has been started
+    // yet. Only the very first `sync_peer` call should start it; every
+    // `KeepaliveTick` reschedules itself, so a second chain would double
+    // (and, with N newly-discovered peers, N-fold) the sweep rate.
+    keepalive_started: bool,
+}
+
+impl<T: RaftStoreRouter> SendRunner<T> {
+    pub fn new(env: Arc<Environment>,
+               security: SecurityConfig,
+               scheduler: Scheduler<SendTask>,
+               raft_router: T)
+               -> SendRunner<T> {
+        SendRunner::with_transport(Box::new(GrpcTransport::new(env, security)), scheduler, raft_router)
+    }
+
+    pub fn with_transport(transport: Box<RaftTransport>,
+                           scheduler: Scheduler<SendTask>,
+                           raft_router: T)
+                           -> SendRunner<T> {
         SendRunner {
-            env: env,
+            transport: transport,
             conns: HashMap::default(),
+            peers: HashMap::default(),
+            scheduler: scheduler,
+            raft_router: raft_router,
+            keepalive_started: false,
         }
     }
 
-    fn get_conn(&mut self, addr: SocketAddr, handle: &CoreHandle) -> Result<&Conn> {
-        // TDOO: handle Conn::new() error.
-        let env = self.env.clone();
-        let conn = self.conns
-            .entry(addr)
-            .or_insert_with(|| Conn::new(env.clone(), addr, handle).unwrap());
-        Ok(conn)
+    fn schedule_keepalive_tick(&self) {
+        let scheduler = self.scheduler.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(KEEPALIVE_INTERVAL_MS));
+            if let Err(e) = scheduler.schedule(SendTask::KeepaliveTick) {
+                error!("failed to schedule raft peer keepalive tick: {:?}", e);
+            }
+        });
     }
 
-    fn send(&mut self, t: SendTask, handle: &CoreHandle) -> Result<()> {
-        let conn = try!(self.get_conn(t.addr, handle));
-        box_try!(mpsc::UnboundedSender::send(&conn.stream, t.msg));
-        Ok(())
+    fn report_unreachable(&self, region_id: u64, to_peer_id: u64, to_store_id: u64) {
+        if let Err(e) = self.raft_router.report_unreachable(region_id, to_peer_id, to_store_id) {
+            error!("report peer {} unreachable for region {} failed {:?}",
+                   to_peer_id,
+                   region_id,
+                   e);
+        }
+    }
+
+    fn report_msg_unreachable(&self, msg: &RaftMessage) {
+        self.report_unreachable(msg.get_region_id(),
+                                 msg.get_to_peer().get_id(),
+                                 msg.get_to_peer().get_store_id());
+    }
+
+    fn mark_backoff(&mut self, addr: SocketAddr, err: String) {
+        let entry = self.conns.entry(addr).or_insert_with(ConnEntry::new);
+        entry.record_failure(err, Instant::now());
+    }
+
+    fn ensure_conn(&mut self, store_id: u64, addr: SocketAddr, handle: &CoreHandle) -> Result<()> {
+        if let Some(entry) = self.conns.get(&addr) {
+            if let Some(until) = entry.retry_at() {
+                if Instant::now() < until {
+                    return Err(box_err!("peer {} is in backoff, dropping message", addr));
+                }
+            }
+            if entry.conn.is_some() {
+                return Ok(());
+            }
+        }
+
+        self.conns.entry(addr).or_insert_with(ConnEntry::new).state = ConnState::Connecting;
+        match self.transport.connect(store_id, addr, handle) {
+            Ok(conn) => {
+                let entry = self.conns.get_mut(&addr).unwrap();
+                entry.conn = Some(conn);
+                entry.state = ConnState::Connected;
+                entry.backoff_ms = MIN_BACKOFF_MS;
+                entry.consecutive_failures = 0;
+                entry.last_error = None;
+                Ok(())
+            }
+            Err(e) => {
+                self.mark_backoff(addr, format!("{:?}", e));
+                Err(e)
+            }
+        }
+    }
+
+    fn send_message(&mut self,
+                     store_id: u64,
+                     addr: SocketAddr,
+                     msg: RaftMessage,
+                     priority: MsgPriority,
+                     handle: &CoreHandle)
+                     -> Result<()> {
+        if let Err(e) = self.ensure_conn(store_id, addr, handle) {
+            self.report_msg_unreachable(&msg);
+            return Err(e);
+        }
+
+        let region_id = msg.get_region_id();
+        let to_peer_id = msg.get_to_peer().get_id();
+        self.peers
+            .entry(store_id)
+            .or_insert_with(|| PeerState::new(addr))
+            .regions
+            .insert(region_id, to_peer_id);
+
+        let send_result =
+            self.conns.get(&addr).unwrap().conn.as_ref().unwrap().send(msg.clone(), priority);
+        match send_result {
+            Ok(()) => {
+                let entry = self.conns.get_mut(&addr).unwrap();
+                entry.last_send_time = Some(Instant::now());
+                Ok(())
+            }
+            Err(e) => {
+                // The message is dropped and the region reported
+                // unreachable either way, instead of buffering without
+                // bound. But only a genuine transport/IO failure means the
+                // connection itself is dead and should go into backoff —
+                // one priority lane filling up (backpressure) says nothing
+                // about the other lanes, and backing off the whole `Conn`
+                // would let a full Bulk lane starve Control traffic for up
+                // to `MAX_BACKOFF_MS`.
+                match e {
+                    Error::LaneFull => {}
+                    _ => self.mark_backoff(addr, format!("{:?}", e)),
+                }
+                self.report_msg_unreachable(&msg);
+                Err(e)
+            }
+        }
+    }
+
+    fn sync_peer(&mut self, store_id: u64, addr: SocketAddr, handle: &CoreHandle) {
+        let is_new = !self.peers.contains_key(&store_id);
+        self.peers.entry(store_id).or_insert_with(|| PeerState::new(addr));
+        if is_new {
+            // Proactively dial the peer now instead of waiting for the
+            // first real raft message, so full-mesh connectivity (and its
+            // keepalive) starts as soon as the store is discovered.
+            if let Err(e) = self.ensure_conn(store_id, addr, handle) {
+                debug!("initial connect to peer store {} at {} failed: {:?}", store_id, addr, e);
+            }
+            // `KeepaliveTick` reschedules itself (see `run_keepalive_sweep`),
+            // so only the first peer we ever discover should kick off the
+            // chain; later peers just get swept into the existing one.
+            if !self.keepalive_started {
+                self.keepalive_started = true;
+                self.schedule_keepalive_tick();
+            }
+        }
+    }
+
+    fn run_keepalive(&mut self, store_id: u64, handle: &CoreHandle) {
+        let addr = match self.peers.get(&store_id) {
+            Some(p) => p.addr,
+            None => return,
+        };
+
+        let result = match self.ensure_conn(store_id, addr, handle) {
+            Ok(()) => {
+                let send_result =
+                    self.conns
+                        .get(&addr)
+                        .unwrap()
+                        .conn
+                        .as_ref()
+                        .unwrap()
+                        .send(keepalive_message(), MsgPriority::Control);
+                if let Err(ref e) = send_result {
+                    // As in `send_message`: a full priority lane is just
+                    // backpressure, not evidence the connection is dead, so
+                    // it shouldn't trigger a backoff that would then also
+                    // block Control-priority keepalives on other lanes.
+                    match *e {
+                        Error::LaneFull => {}
+                        _ => self.mark_backoff(addr, format!("{:?}", e)),
+                    }
+                }
+                send_result
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => {
+                let peer = self.peers.get_mut(&store_id).unwrap();
+                peer.consecutive_failures = 0;
+                peer.last_seen = Some(Instant::now());
+                let became_up = !peer.up;
+                peer.up = true;
+                if became_up {
+                    info!("peer store {} is reachable again", store_id);
+                }
+                PEER_UP_GAUGE_VEC.with_label_values(&[&store_id.to_string()]).set(1.0);
+                PEER_LAST_SEEN_GAUGE_VEC.with_label_values(&[&store_id.to_string()]).set(0.0);
+            }
+            Err(e) => {
+                let (newly_down, regions) = {
+                    let peer = self.peers.get_mut(&store_id).unwrap();
+                    peer.consecutive_failures += 1;
+                    let newly_down = peer.up && peer.consecutive_failures >= KEEPALIVE_FAILURE_THRESHOLD;
+                    if newly_down {
+                        peer.up = false;
+                    }
+                    let regions = if newly_down {
+                        peer.regions.iter().map(|(&r, &p)| (r, p)).collect()
+                    } else {
+                        Vec::new()
+                    };
+                    (newly_down, regions)
+                };
+                if newly_down {
+                    warn!("peer store {} marked down after {} consecutive keepalive failures: {:?}",
+                          store_id,
+                          KEEPALIVE_FAILURE_THRESHOLD,
+                          e);
+                    PEER_UP_GAUGE_VEC.with_label_values(&[&store_id.to_string()]).set(0.0);
+                    for (region_id, to_peer_id) in regions {
+                        self.report_unreachable(region_id, to_peer_id, store_id);
+                    }
+                }
+                if let Some(last_seen) = self.peers[&store_id].last_seen {
+                    let ago = duration_to_sec(last_seen.elapsed());
+                    PEER_LAST_SEEN_GAUGE_VEC.with_label_values(&[&store_id.to_string()]).set(ago);
+                }
+            }
+        }
+    }
+
+    fn run_keepalive_sweep(&mut self, handle: &CoreHandle) {
+        let store_ids: Vec<u64> = self.peers.keys().cloned().collect();
+        for store_id in store_ids {
+            self.run_keepalive(store_id, handle);
+        }
+        if !self.peers.is_empty() {
+            self.schedule_keepalive_tick();
+        }
     }
 }
 
-impl FutureRunnable<SendTask> for SendRunner {
+impl<T: RaftStoreRouter> FutureRunnable<SendTask> for SendRunner<T> {
     fn run(&mut self, t: SendTask, handle: &CoreHandle) {
-        let addr = t.addr;
-        if let Err(e) = self.send(t, handle) {
-            error!("send raft message error: {:?}", e);
-            self.conns.remove(&addr);
+        match t {
+            SendTask::Send { store_id, addr, msg, priority } => {
+                if let Err(e) = self.send_message(store_id, addr, msg, priority, handle) {
+                    error!("send raft message error: {:?}", e);
+                }
+            }
+            SendTask::SyncPeer { store_id, addr } => self.sync_peer(store_id, addr, handle),
+            SendTask::KeepaliveTick => self.run_keepalive_sweep(handle),
+        }
+    }
+}
+
+// Content-defined chunking for the snapshot stream: instead of treating a
+// snapshot file as one opaque blob, cut it into variable-sized chunks at
+// data-dependent boundaries so that re-sending a snapshot which mostly
+// overlaps one the receiver already has only needs to transfer the chunks
+// that actually changed.
+const CDC_WINDOW: usize = 48;
+// Target an average chunk size of ~8KiB: cut whenever the low 13 bits of
+// the rolling hash are all zero.
+const CDC_MASK: u64 = (1 << 13) - 1;
+const CDC_MIN_CHUNK: usize = 4 * 1024;
+const CDC_MAX_CHUNK: usize = 64 * 1024;
+
+// A gear/buzhash style rolling hash cutter: as each new byte enters the
+// trailing window, a table-driven rotate-xor update keeps the hash cheap
+// to maintain without rehashing the whole window on every byte.
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    // A fixed xorshift* stream seeds the table deterministically so every
+    // sender and receiver cuts the same file into the same chunks.
+    let mut x: u64 = 0x9e3779b97f4a7c15;
+    for slot in table.iter_mut() {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *slot = x;
+    }
+    table
+}
+
+// Splits `data` into content-defined chunks, returning each chunk's
+// `(start, end)` byte range. Boundaries are clamped to
+// `[CDC_MIN_CHUNK, CDC_MAX_CHUNK]` so a pathological run of matching
+// hashes can't produce a degenerate chunk.
+pub fn cdc_cut(data: &[u8]) -> Vec<(usize, usize)> {
+    let table = gear_table();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut hash: u64 = 0;
+
+    for i in 0..data.len() {
+        hash = hash.rotate_left(1) ^ table[data[i] as usize];
+        // Drop the byte that just left the trailing `CDC_WINDOW`-byte window.
+        if i >= start + CDC_WINDOW {
+            hash ^= table[data[i - CDC_WINDOW] as usize].rotate_left(CDC_WINDOW as u32);
+        }
+        let len = i + 1 - start;
+        if len < CDC_MIN_CHUNK {
+            continue;
+        }
+        if len >= CDC_MAX_CHUNK || (len >= CDC_WINDOW && hash & CDC_MASK == 0) {
+            chunks.push((start, i + 1));
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        chunks.push((start, data.len()));
+    }
+    chunks
+}
+
+// A strong digest identifying a chunk's content, used by the receiver to
+// decide which chunks it already has. This is a stand-in for a proper
+// cryptographic digest (blake2/sha256); any collision-resistant hash
+// works as long as both ends agree on it.
+pub fn cdc_digest(chunk: &[u8]) -> Vec<u8> {
+    let mut state = [0x6a09e667u64, 0xbb67ae85, 0x3c6ef372, 0x510e527f];
+    for (i, b) in chunk.iter().enumerate() {
+        let idx = i % state.len();
+        state[idx] = state[idx].rotate_left(5) ^ (*b as u64).wrapping_mul(0x100000001b3);
+    }
+    let mut digest = Vec::with_capacity(32);
+    for word in &state {
+        for shift in (0..8).rev() {
+            digest.push(((*word >> (shift * 8)) & 0xff) as u8);
         }
     }
+    digest
+}
+
+// Computes the ordered list of chunk digests the sender transmits ahead
+// of the snapshot body, so the receiver can tell it which chunks it
+// already has.
+pub fn cdc_manifest(data: &[u8]) -> Vec<Vec<u8>> {
+    cdc_cut(data).into_iter().map(|(start, end)| cdc_digest(&data[start..end])).collect()
+}
+
+// The current state of a background job, reported to operators through
+// `list_background_jobs`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JobState {
+    // Currently doing work.
+    Active,
+    // Alive but paused.
+    Idle,
+    // Stopped, either cancelled or never started.
+    Dead,
+}
+
+// A snapshot of a background job's status, used for introspection.
+#[derive(Clone, Debug)]
+pub struct BgJobInfo {
+    pub name: String,
+    pub state: JobState,
+    pub last_error: Option<String>,
 }
 
+// Commands accepted by the GC scrub worker. `Tick` is scheduled by the
+// worker onto itself and is never sent by a client.
+pub enum GcTask {
+    Start { safe_point: u64 },
+    Pause,
+    Resume,
+    Cancel,
+    SetTranquility(usize),
+    Tick,
+}
+
+impl fmt::Display for GcTask {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GcTask::Start { safe_point } => write!(f, "start gc, safe_point {}", safe_point),
+            GcTask::Pause => write!(f, "pause gc"),
+            GcTask::Resume => write!(f, "resume gc"),
+            GcTask::Cancel => write!(f, "cancel gc"),
+            GcTask::SetTranquility(v) => write!(f, "set gc tranquility to {}", v),
+            GcTask::Tick => write!(f, "gc tick"),
+        }
+    }
+}
+
+// How many keys the GC scrub worker is allowed to process per tick. Lower
+// values leave more headroom for foreground traffic at the cost of a
+// slower scrub pass; see `GcTask::SetTranquility`.
+const DEFAULT_GC_TRANQUILITY: usize = 128;
+// Upper bound on a client-supplied tranquility
+// (`GcCommandType::SetTranquility` in grpc_service.rs), which is also the
+// per-tick batch size handed to `gc_scrub_batch`.
+const MAX_GC_TRANQUILITY: usize = 1000;
+// Floor on the delay `schedule_tick` computes from tranquility. Clamping
+// only the *input* (`MAX_GC_TRANQUILITY`) still leaves `1000 / (tranquility
+// + 1)` at 0ms (integer division) at that cap, which would spawn tick
+// threads back to back for as long as GC stays active — clamp the computed cadence
+// itself so a high tranquility can only enlarge the batch, not shorten the
+// delay below a sane floor.
+const MIN_GC_TICK_DELAY_MS: u64 = 50;
+
+pub struct GcWorkerState {
+    running: bool,
+    paused: bool,
+    tranquility: usize,
+    safe_point: u64,
+    // The last key the scrub pass finished processing; resumed from here
+    // on the next tick so pausing does not lose progress.
+    cursor: Option<Vec<u8>>,
+    last_error: Option<String>,
+}
+
+impl GcWorkerState {
+    fn new() -> GcWorkerState {
+        GcWorkerState {
+            running: false,
+            paused: false,
+            tranquility: DEFAULT_GC_TRANQUILITY,
+            safe_point: 0,
+            cursor: None,
+            last_error: None,
+        }
+    }
+
+    pub fn info(&self) -> BgJobInfo {
+        let state = if !self.running {
+            JobState::Dead
+        } else if self.paused {
+            JobState::Idle
+        } else {
+            JobState::Active
+        };
+        BgJobInfo {
+            name: "gc".to_owned(),
+            state: state,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+// GcRunner turns GC from a fire-and-forget `async_gc` call into a
+// long-lived worker: it keeps a progress cursor across ticks and can be
+// paused, resumed, cancelled, or rate-limited (tranquility) by an
+// operator without restarting the process.
+pub struct GcRunner {
+    storage: Storage,
+    scheduler: Scheduler<GcTask>,
+    state: Arc<Mutex<GcWorkerState>>,
+}
+
+impl GcRunner {
+    pub fn new(storage: Storage,
+               scheduler: Scheduler<GcTask>,
+               state: Arc<Mutex<GcWorkerState>>)
+               -> GcRunner {
+        GcRunner {
+            storage: storage,
+            scheduler: scheduler,
+            state: state,
+        }
+    }
+
+    fn schedule_tick(&self, tranquility: usize) {
+        let scheduler = self.scheduler.clone();
+        // Tranquility bounds how many keys a tick touches; translate it
+        // into a short sleep so a low tranquility also means a slower
+        // cadence, not just smaller batches.
+        let delay_ms = cmp::max(1000 / (tranquility as u64 + 1), MIN_GC_TICK_DELAY_MS);
+        let delay = Duration::from_millis(delay_ms);
+        thread::spawn(move || {
+            thread::sleep(delay);
+            let _ = scheduler.schedule(GcTask::Tick);
+        });
+    }
+
+    fn run_tick(&mut self) {
+        let (safe_point, tranquility, cursor) = {
+            let state = self.state.lock().unwrap();
+            if !state.running || state.paused {
+                return;
+            }
+            (state.safe_point, state.tranquility, state.cursor.clone())
+        };
+
+        match self.storage.gc_scrub_batch(safe_point, cursor, tranquility) {
+            Ok(next_cursor) => {
+                let mut state = self.state.lock().unwrap();
+                state.last_error = None;
+                state.cursor = next_cursor;
+            }
+            Err(e) => {
+                error!("gc scrub tick failed: {:?}", e);
+                let mut state = self.state.lock().unwrap();
+                state.last_error = Some(format!("{}", e));
+            }
+        }
+
+        self.schedule_tick(tranquility);
+    }
+}
+
+impl FutureRunnable<GcTask> for GcRunner {
+    fn run(&mut self, t: GcTask, _: &CoreHandle) {
+        match t {
+            GcTask::Start { safe_point } => {
+                let tranquility = {
+                    let mut state = self.state.lock().unwrap();
+                    state.running = true;
+                    state.paused = false;
+                    state.safe_point = safe_point;
+                    state.cursor = None;
+                    state.tranquility
+                };
+                self.schedule_tick(tranquility);
+            }
+            GcTask::Pause => {
+                self.state.lock().unwrap().paused = true;
+            }
+            GcTask::Resume => {
+                let tranquility = {
+                    let mut state = self.state.lock().unwrap();
+                    if !state.running {
+                        return;
+                    }
+                    state.paused = false;
+                    state.tranquility
+                };
+                self.schedule_tick(tranquility);
+            }
+            GcTask::Cancel => {
+                let mut state = self.state.lock().unwrap();
+                state.running = false;
+                state.paused = false;
+                state.cursor = None;
+            }
+            GcTask::SetTranquility(v) => {
+                self.state.lock().unwrap().tranquility = cmp::min(v, MAX_GC_TRANQUILITY);
+            }
+            GcTask::Tick => self.run_tick(),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -549,4 +1577,98 @@ mod tests {
         ch.try_send(Msg::Quit).unwrap();
         h.join().unwrap();
     }
+
+    #[test]
+    fn test_cdc_cut_boundaries() {
+        // Empty input cuts no chunks.
+        assert!(cdc_cut(&[]).is_empty());
+
+        // Below `CDC_MIN_CHUNK`, the whole input is a single chunk even
+        // though it's far short of `CDC_MAX_CHUNK`.
+        let small = vec![0u8; CDC_MIN_CHUNK - 1];
+        assert_eq!(cdc_cut(&small), vec![(0, small.len())]);
+
+        // A run of identical bytes long enough to hit `CDC_MAX_CHUNK`
+        // must still be cut there, even though the rolling hash alone
+        // would never trigger a boundary on constant input.
+        let big = vec![0u8; CDC_MAX_CHUNK * 3 + 17];
+        let chunks = cdc_cut(&big);
+        assert!(chunks.len() >= 3);
+        for &(start, end) in &chunks {
+            assert!(end - start <= CDC_MAX_CHUNK);
+        }
+        // Chunks are contiguous and cover the whole input.
+        assert_eq!(chunks[0].0, 0);
+        assert_eq!(chunks.last().unwrap().1, big.len());
+        for w in chunks.windows(2) {
+            assert_eq!(w[0].1, w[1].0);
+        }
+    }
+
+    #[test]
+    fn test_cdc_manifest_matches_cut() {
+        let data: Vec<u8> = (0..CDC_MAX_CHUNK * 2).map(|i| (i % 251) as u8).collect();
+        let cuts = cdc_cut(&data);
+        let digests = cdc_manifest(&data);
+        assert_eq!(cuts.len(), digests.len());
+        for (&(start, end), digest) in cuts.iter().zip(digests.iter()) {
+            assert_eq!(digest, &cdc_digest(&data[start..end]));
+        }
+    }
+
+    #[test]
+    fn test_priority_of() {
+        let mut snapshot = RaftMessage::new();
+        snapshot.mut_message().set_msg_type(MessageType::MsgSnapshot);
+        assert_eq!(priority_of(&snapshot), MsgPriority::Bulk);
+
+        let mut heartbeat = RaftMessage::new();
+        heartbeat.mut_message().set_msg_type(MessageType::MsgHeartbeat);
+        assert_eq!(priority_of(&heartbeat), MsgPriority::Control);
+
+        let mut vote = RaftMessage::new();
+        vote.mut_message().set_msg_type(MessageType::MsgRequestVote);
+        assert_eq!(priority_of(&vote), MsgPriority::Control);
+
+        let mut append = RaftMessage::new();
+        append.mut_message().set_msg_type(MessageType::MsgAppend);
+        assert_eq!(priority_of(&append), MsgPriority::Normal);
+    }
+
+    #[test]
+    fn test_conn_entry_record_failure_backoff() {
+        let mut entry = ConnEntry::new();
+        assert_eq!(entry.backoff_ms, MIN_BACKOFF_MS);
+
+        let mut prev = entry.backoff_ms;
+        let now = Instant::now();
+        for _ in 0..(FAILED_THRESHOLD - 1) {
+            entry.record_failure("boom".to_owned(), now);
+            // Doubles every time, up to the cap.
+            assert_eq!(entry.backoff_ms, cmp::min(prev * 2, MAX_BACKOFF_MS));
+            prev = entry.backoff_ms;
+            match entry.state {
+                ConnState::Backoff(_) => {}
+                ref other => panic!("expected Backoff before the failure threshold, got {:?}", other),
+            }
+        }
+        assert!(entry.consecutive_failures < FAILED_THRESHOLD);
+
+        // One more failure crosses the threshold and escalates to `Failed`.
+        entry.record_failure("boom".to_owned(), now);
+        assert_eq!(entry.consecutive_failures, FAILED_THRESHOLD);
+        match entry.state {
+            ConnState::Failed(_) => {}
+            ref other => panic!("expected Failed after {} consecutive failures, got {:?}",
+                                 FAILED_THRESHOLD,
+                                 other),
+        }
+
+        // Backoff never exceeds the cap no matter how many more failures
+        // pile up.
+        for _ in 0..10 {
+            entry.record_failure("boom".to_owned(), now);
+        }
+        assert_eq!(entry.backoff_ms, MAX_BACKOFF_MS);
+    }
 }
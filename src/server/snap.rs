@@ -0,0 +1,328 @@
+// Copyright 2017 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::boxed::FnBox;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use mio::Token;
+use protobuf::RepeatedField;
+use futures::{Future, Stream};
+use tokio_core::reactor::Core;
+use grpc::{ChannelBuilder, Environment};
+use kvproto::raft_serverpb::*;
+use kvproto::tikvpb_grpc::TikvClient;
+
+use util::worker::Runnable;
+use util::buf::PipeBuffer;
+use util::collections::{HashMap, HashSet};
+use util::transport::SendCh;
+
+use raftstore::store::SnapManager;
+
+use super::{ConnData, Msg, Result};
+use super::transport::RaftStoreRouter;
+use super::server::{cdc_cut, cdc_manifest};
+
+// Only used to size the initial reassembly buffer in `Runner::close`;
+// picking the wrong value just costs a realloc or two, never correctness.
+const CDC_ASSUMED_CHUNK_SIZE: usize = 8 * 1024;
+
+// Commands accepted by the snapshot worker. `SendTo` drives the sender
+// side of a transfer; the rest drive the receiver side of one, indexed by
+// the per-connection `Token` the `snapshot` RPC handler in
+// `grpc_service.rs` assigns to each inbound stream.
+pub enum Task {
+    SendTo {
+        addr: SocketAddr,
+        data: ConnData,
+        cb: Box<FnBox(Result<()>) + Send>,
+    },
+    Register(Token, RaftMessage),
+    // A sender-offered ordered list of chunk digests (see `cdc_manifest`
+    // in `server.rs`); replies on the callback with the indices of the
+    // chunks this node doesn't already have cached.
+    Manifest(Token, Vec<Vec<u8>>, Box<FnBox(Vec<u32>) + Send>),
+    Write(Token, PipeBuffer),
+    Close(Token),
+    Discard(Token),
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Task::SendTo { addr, .. } => write!(f, "send snapshot to {}", addr),
+            Task::Register(token, _) => write!(f, "register snapshot {:?}", token),
+            Task::Manifest(token, ref digests, _) => {
+                write!(f, "snapshot {:?} manifest, {} chunks", token, digests.len())
+            }
+            Task::Write(token, _) => write!(f, "write snapshot {:?} chunk", token),
+            Task::Close(token) => write!(f, "close snapshot {:?}", token),
+            Task::Discard(token) => write!(f, "discard snapshot {:?}", token),
+        }
+    }
+}
+
+// An inbound transfer in progress.
+struct RecvSession {
+    msg: Option<RaftMessage>,
+    // The full ordered list of chunk digests the sender offered; used on
+    // `Close` to reassemble the snapshot body out of `chunk_cache`.
+    digests: Vec<Vec<u8>>,
+    // Digests still awaiting their chunk body, in the order `Write` will
+    // deliver them.
+    pending: Vec<Vec<u8>>,
+}
+
+impl RecvSession {
+    fn new() -> RecvSession {
+        RecvSession {
+            msg: None,
+            digests: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+// Caps how much snapshot chunk data a single `Runner` keeps around; a
+// long-running store that transfers many large snapshots would otherwise
+// grow `chunk_cache` without bound. 256MiB is generous enough that most
+// clusters never evict anything in normal operation, while still bounding
+// worst-case memory for a store under constant snapshot churn.
+const CHUNK_CACHE_CAPACITY_BYTES: usize = 256 * 1024 * 1024;
+
+// A content-addressed store of chunk bodies, bounded by total byte size.
+// Eviction is oldest-inserted-first rather than a full LRU: `Runner` only
+// ever looks a chunk up once per transfer (to decide whether to send it,
+// or to reassemble a receive), so recency of *insertion* already tracks
+// recency of use closely enough without the bookkeeping of bumping an
+// entry on every read.
+struct ChunkCache {
+    entries: HashMap<Vec<u8>, Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+    bytes: usize,
+}
+
+impl ChunkCache {
+    fn new() -> ChunkCache {
+        ChunkCache {
+            entries: HashMap::default(),
+            order: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    fn contains_key(&self, digest: &[u8]) -> bool {
+        self.entries.contains_key(digest)
+    }
+
+    fn get(&self, digest: &[u8]) -> Option<&Vec<u8>> {
+        self.entries.get(digest)
+    }
+
+    fn insert(&mut self, digest: Vec<u8>, chunk: Vec<u8>) {
+        if self.entries.contains_key(&digest) {
+            return;
+        }
+        self.bytes += chunk.len();
+        self.order.push_back(digest.clone());
+        self.entries.insert(digest, chunk);
+
+        while self.bytes > CHUNK_CACHE_CAPACITY_BYTES {
+            let oldest = match self.order.pop_front() {
+                Some(d) => d,
+                None => break,
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.bytes -= evicted.len();
+            }
+        }
+    }
+}
+
+// Runner drives both ends of the content-defined-chunking snapshot
+// protocol: it cuts and offers chunks when sending (`SendTo`), and
+// answers "which of these do you already have" / assembles the body when
+// receiving (`Manifest`/`Write`/`Close`).
+pub struct Runner<T: RaftStoreRouter> {
+    env: Arc<Environment>,
+    snap_mgr: SnapManager,
+    raft_router: T,
+    ch: SendCh<Msg>,
+    sessions: HashMap<Token, RecvSession>,
+    // Content-addressed store of every chunk this node has sent or
+    // received at least once, so re-transferring a snapshot that mostly
+    // overlaps a previous one only has to move the chunks that changed.
+    // Bounded by `CHUNK_CACHE_CAPACITY_BYTES`; see `ChunkCache`.
+    chunk_cache: ChunkCache,
+}
+
+impl<T: RaftStoreRouter> Runner<T> {
+    pub fn new(snap_mgr: SnapManager, raft_router: T, ch: SendCh<Msg>) -> Runner<T> {
+        Runner {
+            env: Arc::new(Environment::new(1)),
+            snap_mgr: snap_mgr,
+            raft_router: raft_router,
+            ch: ch,
+            sessions: HashMap::default(),
+            chunk_cache: ChunkCache::new(),
+        }
+    }
+
+    fn send_to(&mut self, addr: SocketAddr, data: ConnData, cb: Box<FnBox(Result<()>) + Send>) {
+        let raw = data.msg.get_message().get_snapshot().get_data().to_vec();
+        // `cdc_manifest` is the digest list we actually offer the peer;
+        // re-deriving the byte ranges with `cdc_cut` is the same boundary
+        // computation, just kept around so we have the chunk bodies too.
+        let digests = cdc_manifest(&raw);
+        let chunks: Vec<Vec<u8>> = cdc_cut(&raw)
+            .into_iter()
+            .map(|(start, end)| raw[start..end].to_vec())
+            .collect();
+
+        let result = self.do_send(addr, data.msg, chunks, digests);
+        if let Err(ref e) = result {
+            error!("send snapshot to {} failed: {:?}", addr, e);
+        }
+        cb(result);
+    }
+
+    // Offers the manifest first and only streams the chunk bodies the
+    // peer reports missing, caching every chunk (sent or already-known)
+    // so a later snapshot this node receives can skip chunks it holds.
+    fn do_send(&mut self,
+               addr: SocketAddr,
+               msg: RaftMessage,
+               chunks: Vec<Vec<u8>>,
+               digests: Vec<Vec<u8>>)
+               -> Result<()> {
+        let channel = ChannelBuilder::new(self.env.clone()).connect(&format!("{}", addr));
+        let client = TikvClient::new(channel);
+        let (sink, receiver) = client.snapshot();
+
+        let mut manifest = Manifest::new();
+        manifest.set_digests(RepeatedField::from_vec(digests.clone()));
+        let mut manifest_chunk = SnapshotChunk::new();
+        manifest_chunk.set_manifest(manifest);
+
+        let mut core = box_try!(Core::new());
+        let sink = box_try!(core.run(sink.send(manifest_chunk)));
+
+        let (reply, receiver) = box_try!(core.run(receiver.into_future()
+            .map_err(|(e, _)| e)));
+        let missing: HashSet<u32> = match reply {
+            Some(mut chunk) => chunk.take_missing().take_indices().into_iter().collect(),
+            None => return Err(box_err!("peer closed snapshot stream before replying with missing chunks")),
+        };
+        drop(receiver);
+
+        let mut msg_chunk = SnapshotChunk::new();
+        msg_chunk.set_message(msg);
+        let mut sink = box_try!(core.run(sink.send(msg_chunk)));
+
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            self.chunk_cache.insert(digests[i].clone(), chunk.clone());
+            if missing.contains(&(i as u32)) {
+                let mut data_chunk = SnapshotChunk::new();
+                data_chunk.set_data(chunk);
+                sink = box_try!(core.run(sink.send(data_chunk)));
+            }
+        }
+        box_try!(core.run(sink.close()));
+        Ok(())
+    }
+
+    fn manifest(&mut self, token: Token, digests: Vec<Vec<u8>>, cb: Box<FnBox(Vec<u32>) + Send>) {
+        let missing: Vec<u32> = digests.iter()
+            .enumerate()
+            .filter(|&(_, d)| !self.chunk_cache.contains_key(d))
+            .map(|(i, _)| i as u32)
+            .collect();
+
+        let session = self.sessions.entry(token).or_insert_with(RecvSession::new);
+        session.pending = missing.iter().map(|&i| digests[i as usize].clone()).collect();
+        session.digests = digests;
+        cb(missing);
+    }
+
+    fn register(&mut self, token: Token, msg: RaftMessage) {
+        self.sessions.entry(token).or_insert_with(RecvSession::new).msg = Some(msg);
+    }
+
+    fn write(&mut self, token: Token, mut buf: PipeBuffer) {
+        let session = match self.sessions.get_mut(&token) {
+            Some(s) => s,
+            None => return,
+        };
+        if session.pending.is_empty() {
+            warn!("received an unexpected snapshot chunk for {:?}", token);
+            return;
+        }
+        let digest = session.pending.remove(0);
+        let mut data = Vec::new();
+        if let Err(e) = buf.read_to_end(&mut data) {
+            error!("read snapshot chunk for {:?} failed: {:?}", token, e);
+            return;
+        }
+        self.chunk_cache.insert(digest, data);
+    }
+
+    fn close(&mut self, token: Token) {
+        let session = match self.sessions.remove(&token) {
+            Some(s) => s,
+            None => return,
+        };
+        let mut data = Vec::with_capacity(session.digests.len() * CDC_ASSUMED_CHUNK_SIZE);
+        for digest in &session.digests {
+            match self.chunk_cache.get(digest) {
+                Some(chunk) => data.extend_from_slice(chunk),
+                None => {
+                    error!("missing cached chunk while assembling snapshot {:?}", token);
+                    return;
+                }
+            }
+        }
+
+        let mut msg = match session.msg {
+            Some(msg) => msg,
+            None => {
+                error!("snapshot {:?} closed without ever registering its message", token);
+                return;
+            }
+        };
+        msg.mut_message().mut_snapshot().set_data(data);
+        if let Err(e) = self.raft_router.send_raft_msg(msg) {
+            error!("send received snapshot {:?} to raftstore failed: {:?}", token, e);
+        }
+    }
+
+    fn discard(&mut self, token: Token) {
+        self.sessions.remove(&token);
+    }
+}
+
+impl<T: RaftStoreRouter> Runnable<Task> for Runner<T> {
+    fn run(&mut self, task: Task) {
+        match task {
+            Task::SendTo { addr, data, cb } => self.send_to(addr, data, cb),
+            Task::Register(token, msg) => self.register(token, msg),
+            Task::Manifest(token, digests, cb) => self.manifest(token, digests, cb),
+            Task::Write(token, buf) => self.write(token, buf),
+            Task::Close(token) => self.close(token),
+            Task::Discard(token) => self.discard(token),
+        }
+    }
+}